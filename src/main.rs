@@ -9,14 +9,18 @@ use std::{
     io::{stdin, stdout, BufRead, BufReader, Read, Write},
     path::Path,
     process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
     time::{Duration, Instant},
 };
 
 use clap::Parser;
 use humansize::{format_size, DECIMAL};
 use humantime::format_duration;
-use lazy_static::lazy_static;
-use rusqlite::Connection;
+use rusqlite::{backup::Backup, Connection};
 use wikidata::{Entity, Lang, Rank, WikiId};
 
 use crate::{
@@ -28,64 +32,352 @@ use crate::{
 #[global_allocator]
 static ALLOCATOR: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-lazy_static! {
-    static ref ENGLISH: Lang = Lang("en".to_owned());
-}
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Arguments {
     json_file: String,
     sqlite_file: String,
+    /// Build an R*Tree spatial index over the coordinates table
+    #[arg(long)]
+    spatial: bool,
+    /// Build an FTS5 full-text index over labels and descriptions
+    #[arg(long)]
+    fts: bool,
+    /// Tokenizer used for the FTS5 index
+    #[arg(long, default_value = "unicode61")]
+    fts_tokenizer: String,
+    /// Number of parser worker threads (defaults to the number of available CPUs)
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Comma-separated list of language codes to capture labels and descriptions for
+    #[arg(long, default_value = "en")]
+    languages: String,
+    /// Update an existing database instead of refusing to touch it
+    #[arg(long)]
+    update: bool,
+    /// Build the database in memory, then write it to disk in a single sequential pass
+    #[arg(long)]
+    memory: bool,
 }
 
-fn create_tables(connection: &Connection) -> rusqlite::Result<()> {
-    connection
-        .execute_batch("CREATE TABLE meta (id INTEGER NOT NULL, label TEXT, description TEXT);")?;
+fn create_tables(
+    connection: &Connection,
+    spatial: bool,
+    fts: bool,
+    fts_tokenizer: &str,
+    multilingual: bool,
+) -> rusqlite::Result<()> {
+    if multilingual {
+        connection.execute_batch(
+            "
+            CREATE TABLE meta (id INTEGER NOT NULL);
+            CREATE TABLE label (id INTEGER NOT NULL, lang TEXT NOT NULL, text TEXT NOT NULL);
+            CREATE TABLE description (id INTEGER NOT NULL, lang TEXT NOT NULL, text TEXT NOT NULL);
+            ",
+        )?;
+    } else {
+        connection.execute_batch(
+            "CREATE TABLE meta (id INTEGER NOT NULL, label TEXT, description TEXT);",
+        )?;
+    }
+
+    connection.execute_batch(
+        "
+        CREATE TABLE claims (id INTEGER PRIMARY KEY, entity_id INTEGER NOT NULL, property_id INTEGER NOT NULL);
+        CREATE TABLE reference_groups (id INTEGER PRIMARY KEY, claim_id INTEGER NOT NULL);
+        ",
+    )?;
 
     for value_type in VALUE_TYPES.iter() {
         value_type.create_table(connection)?;
+        value_type.create_qualifier_table(connection)?;
+        value_type.create_reference_table(connection)?;
+    }
+
+    if spatial {
+        // `coordinates_rtree.id` is the `rowid` of the matching row in the `coordinates`
+        // table, not that table's `id` column (which holds the claim id). Join with
+        // `coordinates_rtree.id = coordinates.rowid`.
+        connection.execute_batch(
+            "CREATE VIRTUAL TABLE coordinates_rtree USING rtree(id, min_lat, max_lat, min_lon, max_lon);",
+        )?;
+    }
+
+    if fts {
+        connection.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE meta_fts USING fts5(label, description, content='meta', content_rowid='id', tokenize='{}');",
+            fts_tokenizer,
+        ))?;
     }
 
     Ok(())
 }
 
-fn create_indices(connection: &Connection) -> rusqlite::Result<()> {
+fn create_indices(connection: &Connection, multilingual: bool) -> rusqlite::Result<()> {
+    if multilingual {
+        connection.execute_batch(
+            "
+            CREATE INDEX meta_id_index ON meta (id);
+            CREATE INDEX label_id_index ON label (id);
+            CREATE INDEX label_lang_index ON label (lang);
+            CREATE INDEX description_id_index ON description (id);
+            CREATE INDEX description_lang_index ON description (lang);
+            ",
+        )?;
+    } else {
+        connection.execute_batch(
+            "
+            CREATE INDEX meta_id_index ON meta (id);
+            CREATE INDEX meta_label_index ON meta (label);
+            CREATE INDEX meta_description_index ON meta (description);
+            ",
+        )?;
+    }
+
     connection.execute_batch(
         "
-        CREATE INDEX meta_id_index ON meta (id);
-        CREATE INDEX meta_label_index ON meta (label);
-        CREATE INDEX meta_description_index ON meta (description);
+        CREATE INDEX claims_entity_id_index ON claims (entity_id);
+        CREATE INDEX claims_property_id_index ON claims (property_id);
+        CREATE INDEX reference_groups_claim_id_index ON reference_groups (claim_id);
         ",
     )?;
 
     for value_type in VALUE_TYPES.iter() {
         value_type.create_indices(connection)?;
+        value_type.create_qualifier_indices(connection)?;
+        value_type.create_reference_indices(connection)?;
     }
 
     Ok(())
 }
 
-fn store_entity(connection: &Connection, entity: Entity) -> rusqlite::Result<()> {
+fn entity_id(entity: &Entity) -> u64 {
     use WikiId::*;
 
-    let id = match entity.id {
+    match entity.id {
         EntityId(id) => q_id(id),
         PropertyId(id) => p_id(id),
         LexemeId(id) => l_id(id),
-    };
+    }
+}
+
+/// Deletes every row previously stored for the entity identified by `id`, including
+/// its claims' mainsnak values, qualifiers, and references, so it can be re-inserted
+/// from a newer dump.
+fn delete_entity(
+    connection: &Connection,
+    id: u64,
+    spatial: bool,
+    multilingual: bool,
+) -> rusqlite::Result<()> {
+    let claim_ids: Vec<i64> = connection
+        .prepare_cached("SELECT id FROM claims WHERE entity_id = ?1")?
+        .query_map((id,), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for claim_id in claim_ids {
+        for value_type in VALUE_TYPES.iter() {
+            value_type.delete_by_claim_id(connection, claim_id as u64)?;
+        }
+
+        let reference_group_ids: Vec<i64> = connection
+            .prepare_cached("SELECT id FROM reference_groups WHERE claim_id = ?1")?
+            .query_map((claim_id,), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        for reference_group_id in reference_group_ids {
+            for value_type in VALUE_TYPES.iter() {
+                value_type.delete_by_reference_group_id(connection, reference_group_id as u64)?;
+            }
+        }
+
+        connection
+            .prepare_cached("DELETE FROM reference_groups WHERE claim_id = ?1")?
+            .execute((claim_id,))?;
+
+        if spatial {
+            // `coordinates_rtree` rows are keyed by the `rowid` of the matching `coordinates`
+            // row, not by `id`, so they must be collected before that row is deleted below.
+            let coordinates_rowids: Vec<i64> = connection
+                .prepare_cached("SELECT rowid FROM coordinates WHERE id = ?1")?
+                .query_map((claim_id,), |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+
+            for rowid in coordinates_rowids {
+                connection
+                    .prepare_cached("DELETE FROM coordinates_rtree WHERE id = ?1")?
+                    .execute((rowid,))?;
+            }
+        }
+
+        // The mainsnak value tables are keyed by `claim_id` (not by the entity `id`), so a
+        // statement's value can be joined back to the claim it belongs to and, through it,
+        // to that claim's qualifiers and references.
+        for value_type in VALUE_TYPES.iter() {
+            value_type.delete_by_id(connection, claim_id as u64)?;
+        }
+    }
 
     connection
-        .prepare_cached("INSERT INTO meta (id, label, description) VALUES (?1, ?2, ?3)")?
-        .execute((
-            id,
-            entity.labels.get(&ENGLISH),
-            entity.descriptions.get(&ENGLISH),
-        ))?;
+        .prepare_cached("DELETE FROM claims WHERE entity_id = ?1")?
+        .execute((id,))?;
+
+    connection
+        .prepare_cached("DELETE FROM meta WHERE id = ?1")?
+        .execute((id,))?;
+
+    if multilingual {
+        connection
+            .prepare_cached("DELETE FROM label WHERE id = ?1")?
+            .execute((id,))?;
+        connection
+            .prepare_cached("DELETE FROM description WHERE id = ?1")?
+            .execute((id,))?;
+    }
+
+    Ok(())
+}
+
+/// Checks whether `create_indices` has already been run against this database.
+fn indices_exist(connection: &Connection) -> rusqlite::Result<bool> {
+    let count: i64 = connection.query_row(
+        "SELECT count(*) FROM pragma_index_list('meta')",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok(count > 0)
+}
+
+/// Checks that an existing database (being opened with `--update`) was built with flags
+/// compatible with the ones passed for this run, so a mismatch is reported once up front
+/// instead of failing on every entity once ingestion is under way.
+fn check_schema(
+    connection: &Connection,
+    spatial: bool,
+    fts: bool,
+    multilingual: bool,
+) -> rusqlite::Result<Option<&'static str>> {
+    let table_exists = |name: &str| -> rusqlite::Result<bool> {
+        let count: i64 = connection.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            (name,),
+            |row| row.get(0),
+        )?;
+
+        Ok(count > 0)
+    };
+
+    if multilingual && !table_exists("label")? {
+        return Ok(Some(
+            "The database was built with a single --languages entry, but this run was \
+             passed more than one. Use the same --languages value as the original run.",
+        ));
+    }
+
+    if !multilingual && table_exists("label")? {
+        return Ok(Some(
+            "The database was built with more than one --languages entry, but this run \
+             was passed only one. Use the same --languages value as the original run.",
+        ));
+    }
+
+    if fts && !table_exists("meta_fts")? {
+        return Ok(Some(
+            "--fts was passed, but the database was not originally built with --fts.",
+        ));
+    }
+
+    if spatial && !table_exists("coordinates_rtree")? {
+        return Ok(Some(
+            "--spatial was passed, but the database was not originally built with --spatial.",
+        ));
+    }
+
+    if !spatial && table_exists("coordinates_rtree")? {
+        return Ok(Some(
+            "The database was originally built with --spatial, but this run was not \
+             passed --spatial. Pass --spatial to keep the rtree index up to date.",
+        ));
+    }
+
+    Ok(None)
+}
+
+fn store_entity(
+    connection: &Connection,
+    id: u64,
+    entity: Entity,
+    spatial: bool,
+    languages: &[Lang],
+) -> rusqlite::Result<()> {
+    if let [language] = languages {
+        connection
+            .prepare_cached("INSERT INTO meta (id, label, description) VALUES (?1, ?2, ?3)")?
+            .execute((
+                id,
+                entity.labels.get(language),
+                entity.descriptions.get(language),
+            ))?;
+    } else {
+        connection
+            .prepare_cached("INSERT INTO meta (id) VALUES (?1)")?
+            .execute((id,))?;
+
+        for language in languages {
+            if let Some(label) = entity.labels.get(language) {
+                connection
+                    .prepare_cached("INSERT INTO label (id, lang, text) VALUES (?1, ?2, ?3)")?
+                    .execute((id, &language.0, label))?;
+            }
+
+            if let Some(description) = entity.descriptions.get(language) {
+                connection
+                    .prepare_cached("INSERT INTO description (id, lang, text) VALUES (?1, ?2, ?3)")?
+                    .execute((id, &language.0, description))?;
+            }
+        }
+    }
 
     for (pid, claim_value) in entity.claims {
-        if claim_value.rank != Rank::Deprecated {
-            Value::from(claim_value.data).store(connection, id, p_id(pid))?;
+        if claim_value.rank == Rank::Deprecated {
+            continue;
+        }
+
+        connection
+            .prepare_cached("INSERT INTO claims (entity_id, property_id) VALUES (?1, ?2)")?
+            .execute((id, p_id(pid)))?;
+        let claim_id = connection.last_insert_rowid() as u64;
+
+        for (qualifier_pid, qualifier_values) in claim_value.qualifiers {
+            for qualifier_value in qualifier_values {
+                for value in Value::from_claim_data(qualifier_value, languages) {
+                    value.store_qualifier(connection, claim_id, p_id(qualifier_pid))?;
+                }
+            }
+        }
+
+        for reference in claim_value.references {
+            connection
+                .prepare_cached("INSERT INTO reference_groups (claim_id) VALUES (?1)")?
+                .execute((claim_id,))?;
+            let reference_group_id = connection.last_insert_rowid() as u64;
+
+            for (reference_pid, reference_values) in reference.snaks {
+                for reference_value in reference_values {
+                    for value in Value::from_claim_data(reference_value, languages) {
+                        value.store_reference(
+                            connection,
+                            reference_group_id,
+                            p_id(reference_pid),
+                        )?;
+                    }
+                }
+            }
+        }
+
+        for value in Value::from_claim_data(claim_value.data, languages) {
+            value.store(connection, claim_id, p_id(pid), spatial)?;
         }
     }
 
@@ -95,14 +387,37 @@ fn store_entity(connection: &Connection, entity: Entity) -> rusqlite::Result<()>
 fn main() -> ExitCode {
     let arguments = Arguments::parse();
 
-    if Path::new(&arguments.sqlite_file).exists() {
+    let database_exists = Path::new(&arguments.sqlite_file).exists();
+
+    if database_exists && !arguments.update {
         eprintln!(
-            "The database '{}' already exists. Updating an existing database is not supported. Choose a new filename for the database.",
+            "The database '{}' already exists. Pass --update to update it, or choose a new filename for the database.",
             arguments.sqlite_file,
         );
         return ExitCode::FAILURE;
     }
 
+    if arguments.memory && arguments.update {
+        eprintln!("--memory cannot be combined with --update.");
+        return ExitCode::FAILURE;
+    }
+
+    let languages: Vec<Lang> = arguments
+        .languages
+        .split(',')
+        .map(|language| Lang(language.trim().to_owned()))
+        .collect();
+    let multilingual = languages.len() > 1;
+
+    if arguments.fts && multilingual {
+        eprintln!("--fts can only be combined with a single --languages entry.");
+        return ExitCode::FAILURE;
+    }
+
+    // Building in memory means the working connection never has prior schema or rows,
+    // regardless of whether the destination file on disk already exists.
+    let working_database_exists = database_exists && !arguments.memory;
+
     let start_time = Instant::now();
 
     let print_progress = |entity_count, byte_count, finished| {
@@ -119,7 +434,7 @@ fn main() -> ExitCode {
 
     println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 
-    let reader: Box<dyn Read> = if arguments.json_file == "-" {
+    let reader: Box<dyn Read + Send> = if arguments.json_file == "-" {
         Box::new(stdin())
     } else {
         Box::new(match File::open(&arguments.json_file) {
@@ -136,14 +451,24 @@ fn main() -> ExitCode {
 
     let reader = BufReader::new(reader);
 
-    let connection = match Connection::open(&arguments.sqlite_file) {
-        Ok(connection) => connection,
-        Err(error) => {
-            eprintln!(
-                "Error opening SQLite database '{}': {}",
-                arguments.sqlite_file, error,
-            );
-            return ExitCode::FAILURE;
+    let connection = if arguments.memory {
+        match Connection::open_in_memory() {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("Error creating in-memory database: {}", error);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        match Connection::open(&arguments.sqlite_file) {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!(
+                    "Error opening SQLite database '{}': {}",
+                    arguments.sqlite_file, error,
+                );
+                return ExitCode::FAILURE;
+            }
         }
     };
 
@@ -157,7 +482,25 @@ fn main() -> ExitCode {
         return ExitCode::FAILURE;
     }
 
-    if let Err(error) = create_tables(&connection) {
+    if working_database_exists {
+        match check_schema(&connection, arguments.spatial, arguments.fts, multilingual) {
+            Ok(Some(reason)) => {
+                eprintln!("{}", reason);
+                return ExitCode::FAILURE;
+            }
+            Ok(None) => {}
+            Err(error) => {
+                eprintln!("Error checking existing database schema: {}", error);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else if let Err(error) = create_tables(
+        &connection,
+        arguments.spatial,
+        arguments.fts,
+        &arguments.fts_tokenizer,
+        multilingual,
+    ) {
         eprintln!("Error creating tables: {}", error);
         return ExitCode::FAILURE;
     }
@@ -167,54 +510,113 @@ fn main() -> ExitCode {
         return ExitCode::FAILURE;
     }
 
-    let mut line_number: usize = 0;
-    let mut entity_count: usize = 0;
-    let mut byte_count: usize = 0;
+    // Reader thread reads and pre-processes lines; a pool of worker threads parses
+    // JSON and builds entities from them; this (main) thread is the sole writer,
+    // draining finished entities into the database inside the existing batch
+    // transactions. `Connection` is not `Sync`, so only this thread ever touches it.
+    let worker_count = arguments.threads.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
 
-    for line in reader.lines() {
-        line_number += 1;
+    let byte_count = Arc::new(AtomicUsize::new(0));
 
-        let mut line = match line {
-            Ok(line) => line,
-            Err(error) => {
-                eprintln!("\nError reading line {}: {}", line_number, error);
+    let (line_sender, line_receiver) = mpsc::sync_channel::<(usize, String)>(1000);
+    let line_receiver = Arc::new(Mutex::new(line_receiver));
+
+    let (entity_sender, entity_receiver) = mpsc::sync_channel::<(usize, Entity)>(1000);
+
+    let reader_byte_count = Arc::clone(&byte_count);
+
+    let reader_handle = thread::spawn(move || {
+        let mut line_number: usize = 0;
+
+        for line in reader.lines() {
+            line_number += 1;
+
+            let mut line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    eprintln!("\nError reading line {}: {}", line_number, error);
+                    continue;
+                }
+            };
+
+            let line_length = line.len();
+            reader_byte_count.fetch_add(line_length, Ordering::Relaxed);
+
+            // Skip array delimiters at beginning and end of dump.
+            if line.is_empty() || line == "[" || line == "]" {
                 continue;
             }
-        };
 
-        let line_length = line.len();
-        byte_count += line_length;
+            // Remove trailing comma.
+            if line.ends_with(',') {
+                line.truncate(line_length - 1);
+            }
 
-        // Skip array delimiters at beginning and end of dump.
-        if line.is_empty() || line == "[" || line == "]" {
-            continue;
+            if line_sender.send((line_number, line)).is_err() {
+                break;
+            }
         }
+    });
+
+    let worker_handles: Vec<_> = (0..worker_count.max(1))
+        .map(|_| {
+            let line_receiver = Arc::clone(&line_receiver);
+            let entity_sender = entity_sender.clone();
+
+            thread::spawn(move || loop {
+                let (line_number, mut line) = match line_receiver.lock().unwrap().recv() {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+
+                let value = match unsafe { simd_json::from_str(&mut line) } {
+                    Ok(value) => value,
+                    Err(error) => {
+                        eprintln!("\nError parsing JSON at line {}: {}", line_number, error);
+                        continue;
+                    }
+                };
+
+                let entity = match Entity::from_json(value) {
+                    Ok(entity) => entity,
+                    Err(error) => {
+                        eprintln!(
+                            "\nError parsing entity from JSON at line {}: {:?}",
+                            line_number, error,
+                        );
+                        continue;
+                    }
+                };
+
+                if entity_sender.send((line_number, entity)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
 
-        // Remove trailing comma.
-        if line.ends_with(',') {
-            line.truncate(line_length - 1);
-        }
+    // Drop the main thread's sender so the channel closes once every worker has.
+    drop(entity_sender);
 
-        let value = match unsafe { simd_json::from_str(&mut line) } {
-            Ok(value) => value,
-            Err(error) => {
-                eprintln!("\nError parsing JSON at line {}: {}", line_number, error);
-                continue;
-            }
-        };
+    let mut entity_count: usize = 0;
 
-        let entity = match Entity::from_json(value) {
-            Ok(entity) => entity,
-            Err(error) => {
+    for (line_number, entity) in entity_receiver {
+        let id = entity_id(&entity);
+
+        if arguments.update {
+            if let Err(error) = delete_entity(&connection, id, arguments.spatial, multilingual) {
                 eprintln!(
-                    "\nError parsing entity from JSON at line {}: {:?}",
+                    "\nError deleting existing entity at line {}: {}",
                     line_number, error,
                 );
-                continue;
             }
-        };
+        }
 
-        if let Err(error) = store_entity(&connection, entity) {
+        if let Err(error) = store_entity(&connection, id, entity, arguments.spatial, &languages) {
             eprintln!("\nError storing entity at line {}: {}", line_number, error);
         }
 
@@ -233,20 +635,89 @@ fn main() -> ExitCode {
                 );
             }
 
-            print_progress(entity_count, byte_count, false);
+            print_progress(entity_count, byte_count.load(Ordering::Relaxed), false);
         }
     }
 
+    let _ = reader_handle.join();
+
+    for worker_handle in worker_handles {
+        let _ = worker_handle.join();
+    }
+
     if let Err(error) = connection.execute_batch("END TRANSACTION;") {
         eprintln!("\nError committing transaction: {}", error);
     }
 
-    print_progress(entity_count, byte_count, true);
+    print_progress(entity_count, byte_count.load(Ordering::Relaxed), true);
+
+    if arguments.fts {
+        println!("\nBuilding full-text index...");
+
+        if let Err(error) =
+            connection.execute_batch("INSERT INTO meta_fts(meta_fts) VALUES('rebuild');")
+        {
+            eprintln!("Error building full-text index: {}", error);
+        }
+    }
 
-    println!("\nCreating indices...");
+    let skip_indices = match indices_exist(&connection) {
+        Ok(exists) => exists,
+        Err(error) => {
+            eprintln!("Error checking for existing indices: {}", error);
+            false
+        }
+    };
+
+    if skip_indices {
+        println!("\nIndices already exist, skipping.");
+    } else {
+        println!("\nCreating indices...");
+
+        if let Err(error) = create_indices(&connection, multilingual) {
+            eprintln!("Error creating indices: {}", error);
+        }
+    }
+
+    if arguments.memory {
+        println!("\nWriting database to disk...");
+
+        let mut destination = match Connection::open(&arguments.sqlite_file) {
+            Ok(destination) => destination,
+            Err(error) => {
+                eprintln!(
+                    "Error creating SQLite database '{}': {}",
+                    arguments.sqlite_file, error,
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+
+        let backup_result = Backup::new(&connection, &mut destination).and_then(|backup| {
+            backup.run_to_completion(
+                100,
+                Duration::from_millis(100),
+                Some(|progress| {
+                    print!(
+                        "\x1B[2K\r{}/{} pages written...",
+                        progress.pagecount - progress.remaining,
+                        progress.pagecount,
+                    );
+
+                    let _ = stdout().flush();
+                }),
+            )
+        });
+
+        if let Err(error) = backup_result {
+            eprintln!(
+                "\nError writing database '{}' to disk: {}",
+                arguments.sqlite_file, error,
+            );
+            return ExitCode::FAILURE;
+        }
 
-    if let Err(error) = create_indices(&connection) {
-        eprintln!("Error creating indices: {}", error);
+        print!("\x1B[2K\r");
     }
 
     println!("Finished.");
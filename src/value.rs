@@ -4,12 +4,9 @@
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use rusqlite::{Connection, Params};
-use wikidata::ClaimValueData;
+use wikidata::{ClaimValueData, Lang};
 
-use crate::{
-    id::{f_id, l_id, p_id, q_id, s_id},
-    ENGLISH,
-};
+use crate::id::{f_id, l_id, p_id, q_id, s_id};
 
 lazy_static! {
     pub static ref VALUE_TYPES: Vec<Value> = vec![
@@ -60,7 +57,11 @@ pub enum Value {
 }
 
 impl Value {
-    fn table_definition(&self) -> (String, Vec<(String, String)>) {
+    fn table_definition(
+        &self,
+        table_prefix: &str,
+        owner_column: &str,
+    ) -> (String, Vec<(String, String)>) {
         use Value::*;
 
         let (table_name, mut value_columns) = match self {
@@ -102,17 +103,22 @@ impl Value {
         };
 
         let mut columns = vec![
-            ("id".to_owned(), "INTEGER NOT NULL".to_owned()),
+            (owner_column.to_owned(), "INTEGER NOT NULL".to_owned()),
             ("property_id".to_owned(), "INTEGER NOT NULL".to_owned()),
         ];
 
         columns.append(&mut value_columns);
 
-        (table_name, columns)
+        (format!("{}{}", table_prefix, table_name), columns)
     }
 
-    pub fn create_table(&self, connection: &Connection) -> rusqlite::Result<()> {
-        let (table_name, columns) = self.table_definition();
+    fn create_table_as(
+        &self,
+        connection: &Connection,
+        table_prefix: &str,
+        owner_column: &str,
+    ) -> rusqlite::Result<()> {
+        let (table_name, columns) = self.table_definition(table_prefix, owner_column);
 
         connection.execute_batch(&format!(
             "CREATE TABLE {} ({});",
@@ -125,8 +131,28 @@ impl Value {
         ))
     }
 
-    pub fn create_indices(&self, connection: &Connection) -> rusqlite::Result<()> {
-        let (table_name, columns) = self.table_definition();
+    /// Table holding mainsnak values, keyed by the `id` of the claim they belong to.
+    pub fn create_table(&self, connection: &Connection) -> rusqlite::Result<()> {
+        self.create_table_as(connection, "", "id")
+    }
+
+    /// Table holding qualifier snaks, keyed by the `claim_id` of the claim they qualify.
+    pub fn create_qualifier_table(&self, connection: &Connection) -> rusqlite::Result<()> {
+        self.create_table_as(connection, "qualifier_", "claim_id")
+    }
+
+    /// Table holding reference snaks, keyed by the `reference_group_id` of the reference they belong to.
+    pub fn create_reference_table(&self, connection: &Connection) -> rusqlite::Result<()> {
+        self.create_table_as(connection, "reference_", "reference_group_id")
+    }
+
+    fn create_indices_as(
+        &self,
+        connection: &Connection,
+        table_prefix: &str,
+        owner_column: &str,
+    ) -> rusqlite::Result<()> {
+        let (table_name, columns) = self.table_definition(table_prefix, owner_column);
 
         for (column_name, _) in columns {
             connection.execute_batch(&format!(
@@ -138,8 +164,26 @@ impl Value {
         Ok(())
     }
 
-    fn store_params(&self, connection: &Connection, params: impl Params) -> rusqlite::Result<()> {
-        let (table_name, columns) = self.table_definition();
+    pub fn create_indices(&self, connection: &Connection) -> rusqlite::Result<()> {
+        self.create_indices_as(connection, "", "id")
+    }
+
+    pub fn create_qualifier_indices(&self, connection: &Connection) -> rusqlite::Result<()> {
+        self.create_indices_as(connection, "qualifier_", "claim_id")
+    }
+
+    pub fn create_reference_indices(&self, connection: &Connection) -> rusqlite::Result<()> {
+        self.create_indices_as(connection, "reference_", "reference_group_id")
+    }
+
+    fn store_params(
+        &self,
+        connection: &Connection,
+        table_prefix: &str,
+        owner_column: &str,
+        params: impl Params,
+    ) -> rusqlite::Result<()> {
+        let (table_name, columns) = self.table_definition(table_prefix, owner_column);
 
         connection
             .prepare_cached(&format!(
@@ -160,17 +204,29 @@ impl Value {
         Ok(())
     }
 
-    pub fn store(
+    fn store_as(
         &self,
         connection: &Connection,
-        id: u64,
+        table_prefix: &str,
+        owner_column: &str,
+        owner_id: u64,
         property_id: u64,
     ) -> rusqlite::Result<()> {
         use Value::*;
 
         match self {
-            String(string) => self.store_params(connection, (id, property_id, string)),
-            Entity(entity_id) => self.store_params(connection, (id, property_id, entity_id)),
+            String(string) => self.store_params(
+                connection,
+                table_prefix,
+                owner_column,
+                (owner_id, property_id, string),
+            ),
+            Entity(entity_id) => self.store_params(
+                connection,
+                table_prefix,
+                owner_column,
+                (owner_id, property_id, entity_id),
+            ),
             Coordinates {
                 latitude,
                 longitude,
@@ -178,7 +234,16 @@ impl Value {
                 globe_id,
             } => self.store_params(
                 connection,
-                (id, property_id, latitude, longitude, precision, globe_id),
+                table_prefix,
+                owner_column,
+                (
+                    owner_id,
+                    property_id,
+                    latitude,
+                    longitude,
+                    precision,
+                    globe_id,
+                ),
             ),
             Quantity {
                 amount,
@@ -187,75 +252,208 @@ impl Value {
                 unit_id,
             } => self.store_params(
                 connection,
-                (id, property_id, amount, lower_bound, upper_bound, unit_id),
+                table_prefix,
+                owner_column,
+                (
+                    owner_id,
+                    property_id,
+                    amount,
+                    lower_bound,
+                    upper_bound,
+                    unit_id,
+                ),
+            ),
+            Time { time, precision } => self.store_params(
+                connection,
+                table_prefix,
+                owner_column,
+                (owner_id, property_id, time, precision),
             ),
-            Time { time, precision } => {
-                self.store_params(connection, (id, property_id, time, precision))
+            None => self.store_params(
+                connection,
+                table_prefix,
+                owner_column,
+                (owner_id, property_id),
+            ),
+            Unknown => self.store_params(
+                connection,
+                table_prefix,
+                owner_column,
+                (owner_id, property_id),
+            ),
+        }
+    }
+
+    /// Stores the mainsnak value of the claim identified by `claim_id` (its row in the
+    /// `claims` table), so the value can be joined back to that claim and, through it, to
+    /// the claim's qualifiers and references — a bare entity `id` is not unique enough for
+    /// this, since an entity can carry more than one claim for the same property. If
+    /// `spatial` is set and this is a `Coordinates` value, also inserts a matching row into
+    /// `coordinates_rtree`, keyed by the `rowid` of the row just inserted into `coordinates`
+    /// (not by `claim_id`) — join with `coordinates_rtree.id = coordinates.rowid`.
+    pub fn store(
+        &self,
+        connection: &Connection,
+        claim_id: u64,
+        property_id: u64,
+        spatial: bool,
+    ) -> rusqlite::Result<()> {
+        self.store_as(connection, "", "id", claim_id, property_id)?;
+
+        if spatial {
+            if let Value::Coordinates {
+                latitude,
+                longitude,
+                ..
+            } = self
+            {
+                connection
+                    .prepare_cached(
+                        "INSERT INTO coordinates_rtree (id, min_lat, max_lat, min_lon, max_lon) \
+                         VALUES (?1, ?2, ?2, ?3, ?3)",
+                    )?
+                    .execute((connection.last_insert_rowid(), latitude, longitude))?;
             }
-            None => self.store_params(connection, (id, property_id)),
-            Unknown => self.store_params(connection, (id, property_id)),
         }
+
+        Ok(())
+    }
+
+    /// Stores a qualifier snak of the claim identified by `claim_id`.
+    pub fn store_qualifier(
+        &self,
+        connection: &Connection,
+        claim_id: u64,
+        property_id: u64,
+    ) -> rusqlite::Result<()> {
+        self.store_as(connection, "qualifier_", "claim_id", claim_id, property_id)
+    }
+
+    /// Stores a reference snak belonging to the reference group identified by `reference_group_id`.
+    pub fn store_reference(
+        &self,
+        connection: &Connection,
+        reference_group_id: u64,
+        property_id: u64,
+    ) -> rusqlite::Result<()> {
+        self.store_as(
+            connection,
+            "reference_",
+            "reference_group_id",
+            reference_group_id,
+            property_id,
+        )
+    }
+
+    fn delete_by(
+        &self,
+        connection: &Connection,
+        table_prefix: &str,
+        owner_column: &str,
+        owner_id: u64,
+    ) -> rusqlite::Result<()> {
+        let (table_name, _) = self.table_definition(table_prefix, owner_column);
+
+        connection
+            .prepare_cached(&format!(
+                "DELETE FROM {} WHERE {} = ?1",
+                table_name, owner_column,
+            ))?
+            .execute((owner_id,))?;
+
+        Ok(())
+    }
+
+    /// Deletes the mainsnak value row previously stored for the claim identified by
+    /// `claim_id`.
+    pub fn delete_by_id(&self, connection: &Connection, claim_id: u64) -> rusqlite::Result<()> {
+        self.delete_by(connection, "", "id", claim_id)
+    }
+
+    /// Deletes all qualifier rows previously stored for the claim identified by `claim_id`.
+    pub fn delete_by_claim_id(
+        &self,
+        connection: &Connection,
+        claim_id: u64,
+    ) -> rusqlite::Result<()> {
+        self.delete_by(connection, "qualifier_", "claim_id", claim_id)
+    }
+
+    /// Deletes all reference rows previously stored for the reference group identified by
+    /// `reference_group_id`.
+    pub fn delete_by_reference_group_id(
+        &self,
+        connection: &Connection,
+        reference_group_id: u64,
+    ) -> rusqlite::Result<()> {
+        self.delete_by(
+            connection,
+            "reference_",
+            "reference_group_id",
+            reference_group_id,
+        )
     }
 }
 
-impl From<ClaimValueData> for Value {
-    fn from(claim_value_data: ClaimValueData) -> Self {
+impl Value {
+    /// Converts a snak's data into the zero or more `Value`s to store for it.
+    ///
+    /// This is almost always exactly one value, the exception being `MultilingualText`,
+    /// which yields one `Value` per requested language the snak actually has a variant for.
+    pub fn from_claim_data(claim_value_data: ClaimValueData, languages: &[Lang]) -> Vec<Self> {
         use ClaimValueData::*;
 
         match claim_value_data {
-            CommonsMedia(string) => Self::String(string),
+            CommonsMedia(string) => vec![Self::String(string)],
             GlobeCoordinate {
                 lat,
                 lon,
                 precision,
                 globe,
-            } => Self::Coordinates {
+            } => vec![Self::Coordinates {
                 latitude: lat,
                 longitude: lon,
                 precision,
                 globe_id: q_id(globe),
-            },
-            Item(id) => Self::Entity(q_id(id)),
-            Property(id) => Self::Entity(p_id(id)),
-            String(string) => Self::String(string),
-            MonolingualText(text) => Self::String(text.text),
-            MultilingualText(texts) => {
-                for text in texts {
-                    if text.lang.0 == ENGLISH.0 {
-                        return Self::String(text.text);
-                    }
-                }
-                Self::None
-            }
-            ExternalID(string) => Self::String(string),
+            }],
+            Item(id) => vec![Self::Entity(q_id(id))],
+            Property(id) => vec![Self::Entity(p_id(id))],
+            String(string) => vec![Self::String(string)],
+            MonolingualText(text) => vec![Self::String(text.text)],
+            MultilingualText(texts) => texts
+                .into_iter()
+                .filter(|text| languages.iter().any(|language| language.0 == text.lang.0))
+                .map(|text| Self::String(text.text))
+                .collect(),
+            ExternalID(string) => vec![Self::String(string)],
             Quantity {
                 amount,
                 lower_bound,
                 upper_bound,
                 unit,
-            } => Self::Quantity {
+            } => vec![Self::Quantity {
                 amount,
                 lower_bound,
                 upper_bound,
                 unit_id: unit.map(q_id),
-            },
+            }],
             DateTime {
                 date_time,
                 precision,
-            } => Self::Time {
+            } => vec![Self::Time {
                 time: date_time,
                 precision,
-            },
-            Url(string) => Self::String(string),
-            MathExpr(string) => Self::String(string),
-            GeoShape(string) => Self::String(string),
-            MusicNotation(string) => Self::String(string),
-            TabularData(string) => Self::String(string),
-            Lexeme(id) => Self::Entity(l_id(id)),
-            Form(id) => Self::Entity(f_id(id)),
-            Sense(id) => Self::Entity(s_id(id)),
-            NoValue => Self::None,
-            UnknownValue => Self::Unknown,
+            }],
+            Url(string) => vec![Self::String(string)],
+            MathExpr(string) => vec![Self::String(string)],
+            GeoShape(string) => vec![Self::String(string)],
+            MusicNotation(string) => vec![Self::String(string)],
+            TabularData(string) => vec![Self::String(string)],
+            Lexeme(id) => vec![Self::Entity(l_id(id))],
+            Form(id) => vec![Self::Entity(f_id(id))],
+            Sense(id) => vec![Self::Entity(s_id(id))],
+            NoValue => vec![Self::None],
+            UnknownValue => vec![Self::Unknown],
         }
     }
 }